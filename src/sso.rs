@@ -1,77 +1,291 @@
 use chrono::Utc;
+use std::collections::HashMap;
 use std::sync::RwLock;
 use std::time::Duration;
 use url::Url;
 
-use jsonwebtoken::{DecodingKey, Validation};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use mini_moka::sync::Cache;
 use once_cell::sync::Lazy;
-use openidconnect::core::{CoreClient, CoreProviderMetadata, CoreResponseType, CoreUserInfoClaims};
+use openidconnect::core::{
+    CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClient, CoreClientAuthMethod, CoreGenderClaim, CoreGrantType,
+    CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse, CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm, CoreResponseMode, CoreResponseType,
+    CoreSubjectIdentifierType,
+};
 use openidconnect::reqwest::async_http_client;
 use openidconnect::{
-    AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IdToken, Nonce, OAuth2TokenResponse,
-    RefreshToken, Scope,
+    AdditionalClaims, AdditionalProviderMetadata, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret,
+    CsrfToken, IdToken, Nonce, OAuth2TokenResponse, ProviderMetadata, RefreshToken, Scope, UserInfoClaims,
 };
+use serde::de::DeserializeOwned;
 
 use crate::{
     api::ApiResult,
     auth,
-    auth::{AuthMethodScope, DEFAULT_REFRESH_VALIDITY},
+    auth::{AuthMethodScope, DEFAULT_REFRESH_VALIDITY, DEFAULT_VALIDITY},
     db::{
-        models::{Device, SsoNonce, User},
+        models::{Device, Organization, SsoNonce, User, UserOrgStatus, UserOrgType, UserOrganization},
         DbConn,
     },
+    http_client::CLIENT,
     CONFIG,
 };
 
 pub static COOKIE_NAME_REDIRECT: &str = "sso_redirect_url";
 
+// Algorithms we're willing to verify a provider-signed token with.
+// RSA and ECDSA only, the provider is never expected to use a symmetric (HMAC) algorithm here.
+static SUPPORTED_ALGORITHMS: &[Algorithm] =
+    &[Algorithm::RS256, Algorithm::RS384, Algorithm::RS512, Algorithm::ES256, Algorithm::ES384];
+
 static AC_CACHE: Lazy<Cache<String, AuthenticatedUser>> =
     Lazy::new(|| Cache::builder().max_capacity(1000).time_to_live(Duration::from_secs(10 * 60)).build());
 
-static CLIENT_CACHE: RwLock<Option<CoreClient>> = RwLock::new(None);
+// One `CoreClient`/metadata pair per configured `idp_id`, so each provider is only discovered once.
+static CLIENT_CACHE: Lazy<RwLock<HashMap<String, CoreClient>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+static METADATA_CACHE: Lazy<RwLock<HashMap<String, SsoProviderMetadata>>> = Lazy::new(|| RwLock::new(HashMap::new()));
 
-static SSO_JWT_VALIDATION: Lazy<Decoding> = Lazy::new(prepare_decoding);
+static SSO_JWT_VALIDATION: Lazy<Decoding> = Lazy::new(Decoding::new);
+
+// Neither `end_session_endpoint` (RP-Initiated Logout) nor `introspection_endpoint` (RFC 7662)
+// are part of `CoreProviderMetadata`, so extend discovery with them.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SsoAdditionalMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_session_endpoint: Option<Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    introspection_endpoint: Option<Url>,
+}
+
+impl AdditionalProviderMetadata for SsoAdditionalMetadata {}
+
+type SsoProviderMetadata = ProviderMetadata<
+    SsoAdditionalMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+// The groups/roles claim is configurable (`sso_groups_claim_name()`), so capture every extra
+// claim instead of declaring a fixed field, and pick the configured one out of it later.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SsoAdditionalClaims {
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
+}
+
+impl AdditionalClaims for SsoAdditionalClaims {}
+
+type SsoUserInfoClaims = UserInfoClaims<SsoAdditionalClaims, CoreGenderClaim>;
+
+// A claim value can be a single string or an array of strings depending on the provider.
+fn claim_values(claim_name: &str, extra: &HashMap<String, serde_json::Value>) -> Vec<String> {
+    match extra.get(claim_name) {
+        Some(serde_json::Value::Array(values)) => {
+            values.iter().filter_map(|v| v.as_str().map(str::to_string)).collect()
+        }
+        Some(serde_json::Value::String(value)) => vec![value.clone()],
+        _ => Vec::new(),
+    }
+}
+
+// Keys fetched from each provider's `jwks_uri`, indexed by `idp_id` then `kid`.
+// Populated lazily on first use and refreshed whenever a `kid` isn't found or the cache goes stale,
+// so provider-side key rotation doesn't require an admin to intervene.
+struct JwksCache {
+    keys: HashMap<String, DecodingKey>,
+    // The JWKS's sole usable key, when it has exactly one. RFC 7517 doesn't require `kid` on a
+    // JWK, and a provider with a single signing key commonly omits it (there's nothing to
+    // disambiguate); a token from such a provider then has no `kid` in its header either, so it
+    // can't be looked up in `keys`. This is the only case it's safe to guess the key without a
+    // `kid` match — with more than one key in the set there'd be no way to tell them apart.
+    singleton: Option<DecodingKey>,
+    fetched_at: i64,
+}
+
+static JWKS_CACHE: Lazy<RwLock<HashMap<String, JwksCache>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+// Optional file-based keys, kept only as a fallback: consulted when the provider's JWKS, once
+// fetched, holds no key for the token's `kid` (or no single key for a token without a `kid`).
+// A failed JWKS fetch is returned as an error without trying them.
+static FALLBACK_KEYS: Lazy<HashMap<String, DecodingKey>> = Lazy::new(prepare_fallback_keys);
 
 // Will Panic if SSO is activated and a key file is present but we can't decode its content
 pub fn pre_load_sso_jwt_validation() {
     Lazy::force(&SSO_JWT_VALIDATION);
+    Lazy::force(&FALLBACK_KEYS);
+}
+
+// Pick the provider to use when the caller didn't carry one through the state/cookie, i.e. a
+// setup with a single configured provider, preserved for backward compatibility.
+fn resolve_idp_id(idp_id: Option<String>) -> ApiResult<String> {
+    match idp_id {
+        Some(idp_id) => Ok(idp_id),
+        None => {
+            let mut idp_ids = CONFIG.sso_idp_ids();
+            match idp_ids.len() {
+                1 => Ok(idp_ids.remove(0)),
+                0 => err!("No SSO provider is configured"),
+                _ => err!("Several SSO providers are configured, idp_id is required"),
+            }
+        }
+    }
 }
 
 // Call the OpenId discovery endpoint to retrieve configuration
-async fn get_client() -> ApiResult<CoreClient> {
-    let client_id = ClientId::new(CONFIG.sso_client_id());
-    let client_secret = ClientSecret::new(CONFIG.sso_client_secret());
+async fn discover_metadata(idp_id: &str) -> ApiResult<SsoProviderMetadata> {
+    let issuer_url = CONFIG.sso_issuer_url(idp_id)?;
 
-    let issuer_url = CONFIG.sso_issuer_url()?;
+    match SsoProviderMetadata::discover_async(issuer_url, async_http_client).await {
+        Err(err) => err!(format!("Failed to discover OpenID provider {idp_id}: {err}")),
+        Ok(metadata) => Ok(metadata),
+    }
+}
 
-    let provider_metadata = match CoreProviderMetadata::discover_async(issuer_url, async_http_client).await {
-        Err(err) => err!(format!("Failed to discover OpenID provider: {err}")),
-        Ok(metadata) => metadata,
-    };
+// Simple cache to prevent recalling the discovery endpoint each time
+async fn cached_metadata(idp_id: &str) -> ApiResult<SsoProviderMetadata> {
+    let cached = METADATA_CACHE.read().ok().and_then(|rw_lock| rw_lock.get(idp_id).cloned());
+    match cached {
+        Some(metadata) => Ok(metadata),
+        None => discover_metadata(idp_id).await.map(|metadata| {
+            let mut cache = METADATA_CACHE.write().unwrap();
+            cache.insert(idp_id.to_string(), metadata.clone());
+            metadata
+        }),
+    }
+}
+
+async fn get_client(idp_id: &str) -> ApiResult<CoreClient> {
+    let client_id = ClientId::new(CONFIG.sso_client_id(idp_id));
+    let client_secret = ClientSecret::new(CONFIG.sso_client_secret(idp_id));
+
+    let provider_metadata = cached_metadata(idp_id).await?;
 
     Ok(CoreClient::from_provider_metadata(provider_metadata, client_id, Some(client_secret))
         .set_redirect_uri(CONFIG.sso_redirect_url()?))
 }
 
 // Simple cache to prevent recalling the discovery endpoint each time
-async fn cached_client() -> ApiResult<CoreClient> {
-    let cc_client = CLIENT_CACHE.read().ok().and_then(|rw_lock| rw_lock.clone());
+async fn cached_client(idp_id: &str) -> ApiResult<CoreClient> {
+    let cc_client = CLIENT_CACHE.read().ok().and_then(|rw_lock| rw_lock.get(idp_id).cloned());
     match cc_client {
         Some(client) => Ok(client),
-        None => get_client().await.map(|client| {
-            let mut cached_client = CLIENT_CACHE.write().unwrap();
-            *cached_client = Some(client.clone());
+        None => get_client(idp_id).await.map(|client| {
+            let mut cache = CLIENT_CACHE.write().unwrap();
+            cache.insert(idp_id.to_string(), client.clone());
             client
         }),
     }
 }
 
-// The `nonce` allow to protect against replay attacks
-pub async fn authorize_url(mut conn: DbConn, state: String) -> ApiResult<Url> {
-    let scopes = CONFIG.sso_scopes_vec().into_iter().map(Scope::new);
+// Fetch a provider's JWKS document (through discovery), index it by `kid`, and separately note
+// its sole key when it has exactly one usable key (see `JwksCache::singleton`).
+async fn fetch_jwks(idp_id: &str) -> ApiResult<(HashMap<String, DecodingKey>, Option<DecodingKey>)> {
+    let provider_metadata = cached_metadata(idp_id).await?;
+
+    let jwks_uri = provider_metadata.jwks_uri().url().clone();
 
-    let (auth_url, _csrf_state, nonce) = cached_client()
+    let response = match CLIENT.get(jwks_uri).send().await {
+        Err(err) => err!(format!("Failed to fetch JWKS for {idp_id}: {err}")),
+        Ok(response) => response,
+    };
+
+    let jwk_set: JwkSet = match response.json().await {
+        Err(err) => err!(format!("Failed to parse JWKS response for {idp_id}: {err}")),
+        Ok(jwk_set) => jwk_set,
+    };
+
+    let mut usable = Vec::new();
+    for jwk in &jwk_set.keys {
+        match DecodingKey::from_jwk(jwk) {
+            Ok(key) => usable.push((jwk.common.key_id.clone(), key)),
+            Err(err) => warn!("Ignoring unusable JWKS entry {:?} for {idp_id}: {err}", jwk.common.key_id),
+        }
+    }
+
+    let singleton = match usable.as_slice() {
+        [(_, key)] => Some(key.clone()),
+        _ => None,
+    };
+    let keys = usable.into_iter().filter_map(|(kid, key)| kid.map(|kid| (kid, key))).collect();
+
+    Ok((keys, singleton))
+}
+
+// Refetch a provider's JWKS and replace its cache entry.
+async fn refresh_jwks_cache(idp_id: &str) -> ApiResult<()> {
+    let (keys, singleton) = fetch_jwks(idp_id).await?;
+    let mut cache = JWKS_CACHE.write().unwrap();
+    cache.insert(
+        idp_id.to_string(),
+        JwksCache {
+            keys,
+            singleton,
+            fetched_at: Utc::now().timestamp(),
+        },
+    );
+    Ok(())
+}
+
+// Resolve the `DecodingKey` to validate a token signed by `idp_id` with `kid` (absent for some
+// providers). Refetches the JWKS once, either because the cache is older than
+// `sso_jwks_ttl_seconds()` or because `kid` isn't known yet, which is exactly what happens right
+// after the provider rotates its signing key.
+async fn decoding_key_for(idp_id: &str, kid: Option<&str>) -> ApiResult<DecodingKey> {
+    let is_stale = |cache: &JwksCache| Utc::now().timestamp() - cache.fetched_at > CONFIG.sso_jwks_ttl_seconds();
+
+    let needs_refresh = {
+        let cache = JWKS_CACHE.read().unwrap();
+        match cache.get(idp_id) {
+            None => true,
+            Some(cache) if is_stale(cache) => true,
+            Some(cache) => match kid {
+                Some(kid) => !cache.keys.contains_key(kid),
+                None => cache.singleton.is_none(),
+            },
+        }
+    };
+
+    if needs_refresh {
+        refresh_jwks_cache(idp_id).await?;
+    }
+
+    if let Some(kid) = kid {
+        if let Some(key) = JWKS_CACHE.read().unwrap().get(idp_id).and_then(|cache| cache.keys.get(kid)).cloned() {
+            return Ok(key);
+        }
+    } else if let Some(key) = JWKS_CACHE.read().unwrap().get(idp_id).and_then(|cache| cache.singleton.clone()) {
+        return Ok(key);
+    }
+
+    if let Some(key) = FALLBACK_KEYS.get(idp_id).cloned() {
+        return Ok(key);
+    }
+
+    err!(format!("No signing key found for {idp_id} (kid {kid:?})"))
+}
+
+// The `nonce` allow to protect against replay attacks. `idp_id` selects which configured provider
+// to authenticate against, defaulting to the single one when only one is configured.
+pub async fn authorize_url(mut conn: DbConn, state: String, idp_id: Option<String>) -> ApiResult<Url> {
+    let idp_id = resolve_idp_id(idp_id)?;
+    let scopes = CONFIG.sso_scopes_vec(&idp_id).into_iter().map(Scope::new);
+
+    let (auth_url, _csrf_state, nonce) = cached_client(&idp_id)
         .await?
         .authorize_url(
             AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
@@ -81,17 +295,42 @@ pub async fn authorize_url(mut conn: DbConn, state: String) -> ApiResult<Url> {
         .add_scopes(scopes)
         .url();
 
-    let sso_nonce = SsoNonce::new(nonce.secret().to_string());
+    let sso_nonce = SsoNonce::new(nonce.secret().to_string(), idp_id);
     sso_nonce.save(&mut conn).await?;
 
     Ok(auth_url)
 }
 
+// Build the provider's RP-Initiated Logout URL so ending a Vaultwarden session also ends the
+// IdP's, instead of leaving it alive for a silent re-login.
+// https://openid.net/specs/openid-connect-rpinitiated-1_0.html
+pub async fn logout_url(idp_id: &str, id_token_hint: String, state: String) -> ApiResult<Url> {
+    if !CONFIG.sso_idp_logout_enabled() {
+        err!("SSO IdP logout is not enabled")
+    }
+
+    let metadata = cached_metadata(idp_id).await?;
+
+    let Some(mut logout_url) = metadata.additional_metadata().end_session_endpoint.clone() else {
+        err!(format!("SSO provider {idp_id} does not expose an end_session_endpoint"))
+    };
+
+    logout_url
+        .query_pairs_mut()
+        .append_pair("id_token_hint", &id_token_hint)
+        .append_pair("post_logout_redirect_uri", &CONFIG.sso_post_logout_redirect_url()?)
+        .append_pair("state", &state);
+
+    Ok(logout_url)
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct IdTokenPayload {
     exp: i64,
     email: Option<String>,
     nonce: String,
+    #[serde(flatten)]
+    extra: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -105,43 +344,118 @@ impl BasicTokenPayload {
     fn nbf(&self) -> i64 {
         self.nbf.or(self.iat).unwrap_or_else(|| Utc::now().naive_utc().timestamp())
     }
+
+    // Treat the token as already expired once it's within `margin` seconds of its `exp`, so we
+    // refresh proactively instead of handing out a token that dies moments after being issued.
+    fn is_expiring(&self, margin: i64) -> bool {
+        self.exp - Utc::now().naive_utc().timestamp() <= margin
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct IntrospectionResponse {
+    active: bool,
+    exp: Option<i64>,
+    nbf: Option<i64>,
+}
+
+// Short-lived cache so a busy client doesn't round-trip to the provider on every single request.
+static INTROSPECTION_CACHE: Lazy<Cache<String, BasicTokenPayload>> =
+    Lazy::new(|| Cache::builder().max_capacity(1000).time_to_live(Duration::from_secs(60)).build());
+
+// Validate an opaque (non-JWT) access token against the provider's introspection endpoint
+// (RFC 7662), for providers that don't issue access tokens we can decode locally.
+async fn introspect_access_token(idp_id: &str, access_token: &str) -> ApiResult<BasicTokenPayload> {
+    if let Some(cached) = INTROSPECTION_CACHE.get(&access_token.to_string()) {
+        return Ok(cached);
+    }
+
+    let metadata = cached_metadata(idp_id).await?;
+    let Some(introspection_endpoint) = metadata.additional_metadata().introspection_endpoint.clone() else {
+        err!(format!("SSO provider {idp_id} does not expose an introspection_endpoint"))
+    };
+
+    let response = match CLIENT
+        .post(introspection_endpoint)
+        .basic_auth(CONFIG.sso_client_id(idp_id), Some(CONFIG.sso_client_secret(idp_id)))
+        .form(&[("token", access_token), ("token_type_hint", "access_token")])
+        .send()
+        .await
+    {
+        Err(err) => err!(format!("Failed to call introspection endpoint for {idp_id}: {err}")),
+        Ok(response) => response,
+    };
+
+    let introspection: IntrospectionResponse = match response.json().await {
+        Err(err) => err!(format!("Failed to parse introspection response for {idp_id}: {err}")),
+        Ok(introspection) => introspection,
+    };
+
+    if !introspection.active {
+        err!("Access token is not active according to the provider")
+    }
+
+    let payload = BasicTokenPayload {
+        iat: None,
+        nbf: introspection.nbf,
+        // The provider didn't assert an expiry: fall back to our own access-token TTL, not the
+        // (much longer) refresh-token one, so we don't mint an access JWT that outlives its kind.
+        exp: introspection.exp.unwrap_or_else(|| (Utc::now().naive_utc() + *DEFAULT_VALIDITY).timestamp()),
+    };
+
+    INTROSPECTION_CACHE.insert(access_token.to_string(), payload.clone());
+
+    Ok(payload)
+}
+
+// Decode the access token locally, falling back to (or, if forced by config, going straight to)
+// provider-side introspection for opaque access tokens that can't be decoded as a JWT.
+async fn validate_access_token(idp_id: &str, access_token: &str) -> ApiResult<BasicTokenPayload> {
+    if CONFIG.sso_force_introspection() {
+        return introspect_access_token(idp_id, access_token).await;
+    }
+
+    // Only opaque tokens (not a JWT we could even attempt to verify locally) fall through to
+    // introspection; a token that *is* a JWT but fails decoding (bad signature, expired, wrong
+    // issuer/audience) is a real failure and must be surfaced as such, not masked by whatever
+    // introspection then reports (including "no introspection_endpoint" for providers without one).
+    if jsonwebtoken::decode_header(access_token).is_err() {
+        return introspect_access_token(idp_id, access_token).await;
+    }
+
+    SSO_JWT_VALIDATION.decode_basic_token(idp_id, "access_token", access_token).await
 }
 
 #[derive(Clone, Debug)]
 pub struct AuthenticatedUser {
+    pub idp_id: String,
     pub nonce: String,
     pub refresh_token: Option<String>,
     pub access_token: String,
+    pub id_token: String,
     pub email: String,
     pub user_name: Option<String>,
+    pub groups: Vec<String>,
 }
 
 struct Decoding {
-    key: DecodingKey,
-    id_validation: Validation,
-    access_validation: Validation,
     debug_key: DecodingKey,
     debug_validation: Validation,
 }
 
 impl Decoding {
-    pub fn new(key: DecodingKey, validation: Validation) -> Self {
-        let mut access_validation = validation.clone();
-        access_validation.validate_aud = false;
-
-        let mut debug_validation = insecure_validation();
+    pub fn new() -> Self {
+        let mut debug_validation = Validation::default();
+        debug_validation.insecure_disable_signature_validation();
         debug_validation.validate_aud = false;
 
         Decoding {
-            key,
-            id_validation: validation,
-            access_validation,
             debug_key: DecodingKey::from_secret(&[]),
             debug_validation,
         }
     }
 
-    pub fn decode_id_token<
+    pub async fn decode_id_token<
         AC: openidconnect::AdditionalClaims,
         GC: openidconnect::GenderClaim,
         JE: openidconnect::JweContentEncryptionAlgorithm<JT>,
@@ -149,15 +463,16 @@ impl Decoding {
         JT: openidconnect::JsonWebKeyType,
     >(
         &self,
+        idp_id: &str,
         oic_id_token: Option<&IdToken<AC, GC, JE, JS, JT>>,
-    ) -> ApiResult<IdTokenPayload> {
+    ) -> ApiResult<(String, IdTokenPayload)> {
         let id_token_str = match oic_id_token {
             None => err!("Token response did not contain an id_token"),
             Some(token) => token.to_string(),
         };
 
-        match jsonwebtoken::decode::<IdTokenPayload>(id_token_str.as_str(), &self.key, &self.id_validation) {
-            Ok(payload) => Ok(payload.claims),
+        match self.decode_signed::<IdTokenPayload>(idp_id, "identity_token", id_token_str.as_str(), true).await {
+            Ok(payload) => Ok((id_token_str, payload)),
             Err(err) => {
                 self.log_decode_debug("identity_token", id_token_str.as_str());
                 err!(format!("Could not decode id token: {err}"))
@@ -165,9 +480,9 @@ impl Decoding {
         }
     }
 
-    pub fn decode_basic_token(&self, token_name: &str, token: &str) -> ApiResult<BasicTokenPayload> {
-        match jsonwebtoken::decode::<BasicTokenPayload>(token, &self.key, &self.access_validation) {
-            Ok(payload) => Ok(payload.claims),
+    pub async fn decode_basic_token(&self, idp_id: &str, token_name: &str, token: &str) -> ApiResult<BasicTokenPayload> {
+        match self.decode_signed::<BasicTokenPayload>(idp_id, token_name, token, false).await {
+            Ok(payload) => Ok(payload),
             Err(err) => {
                 self.log_decode_debug(token_name, token);
                 err_silent!(format!("Could not decode {token_name}: {err}"))
@@ -175,58 +490,83 @@ impl Decoding {
         }
     }
 
+    // Read the JOSE header to pick the right key/algorithm, then verify and decode the claims.
+    async fn decode_signed<T: DeserializeOwned>(
+        &self,
+        idp_id: &str,
+        token_name: &str,
+        token: &str,
+        validate_aud: bool,
+    ) -> ApiResult<T> {
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(header) => header,
+            Err(err) => err!(format!("Could not read {token_name} header: {err}")),
+        };
+
+        if !SUPPORTED_ALGORITHMS.contains(&header.alg) {
+            err!(format!("Unsupported {token_name} signing algorithm: {:?}", header.alg));
+        }
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = 30; // 30 seconds
+        validation.validate_exp = true;
+        validation.validate_nbf = true;
+        validation.validate_aud = validate_aud;
+        if validate_aud {
+            validation.set_audience(&[CONFIG.sso_client_id(idp_id)]);
+        }
+        validation.set_issuer(&[CONFIG.sso_authority(idp_id)]);
+
+        let key = decoding_key_for(idp_id, header.kid.as_deref()).await?;
+
+        match jsonwebtoken::decode::<T>(token, &key, &validation) {
+            Ok(payload) => Ok(payload.claims),
+            Err(err) => err!(format!("Could not decode {token_name}: {err}")),
+        }
+    }
+
     pub fn log_decode_debug(&self, token_name: &str, token: &str) {
         let _ = jsonwebtoken::decode::<serde_json::Value>(token, &self.debug_key, &self.debug_validation)
             .map(|payload| debug!("Token {token_name}: {}", payload.claims));
     }
 }
 
-fn insecure_validation() -> Validation {
-    let mut validation = jsonwebtoken::Validation::default();
-    validation.set_audience(&[CONFIG.sso_client_id()]);
-    validation.insecure_disable_signature_validation();
-
-    validation
-}
-
-// DecodingKey and Validation used to read the SSO JWT token response
-// If there is no key fallback to reading without validation
-fn prepare_decoding() -> Decoding {
-    let maybe_key = CONFIG.sso_enabled().then_some(()).and_then(|_| match std::fs::read(CONFIG.sso_key_filepath()) {
-        Ok(key) => Some(DecodingKey::from_rsa_pem(&key).unwrap_or_else(|e| {
-            panic!(
-                "Failed to decode optional SSO public RSA Key, format should exactly match:\n\
-                -----BEGIN PUBLIC KEY-----\n\
-                ...\n\
-                -----END PUBLIC KEY-----\n\
-                Error: {e}"
-            );
-        })),
-        Err(err) => {
-            println!("[INFO] Can't read optional SSO public key at {} : {err}", CONFIG.sso_key_filepath());
-            None
-        }
-    });
-
-    match maybe_key {
-        Some(key) => {
-            let mut validation = jsonwebtoken::Validation::new(jsonwebtoken::Algorithm::RS256);
-            validation.leeway = 30; // 30 seconds
-            validation.validate_exp = true;
-            validation.validate_nbf = true;
-            validation.set_audience(&[CONFIG.sso_client_id()]);
-            validation.set_issuer(&[CONFIG.sso_authority()]);
-
-            Decoding::new(key, validation)
+// Optional per-provider RSA public key read from `sso_key_filepath(idp_id)`, kept as a
+// fallback now that keys are primarily resolved through each provider's JWKS.
+fn prepare_fallback_keys() -> HashMap<String, DecodingKey> {
+    if !CONFIG.sso_enabled() {
+        return HashMap::new();
+    }
+
+    let mut keys = HashMap::new();
+    for idp_id in CONFIG.sso_idp_ids() {
+        match std::fs::read(CONFIG.sso_key_filepath(&idp_id)) {
+            Ok(key) => {
+                let key = DecodingKey::from_rsa_pem(&key).unwrap_or_else(|e| {
+                    panic!(
+                        "Failed to decode optional SSO public RSA Key for {idp_id}, format should exactly match:\n\
+                        -----BEGIN PUBLIC KEY-----\n\
+                        ...\n\
+                        -----END PUBLIC KEY-----\n\
+                        Error: {e}"
+                    );
+                });
+                keys.insert(idp_id, key);
+            }
+            Err(err) => {
+                println!("[INFO] Can't read optional SSO public key at {} : {err}", CONFIG.sso_key_filepath(&idp_id));
+            }
         }
-        None => Decoding::new(DecodingKey::from_secret(&[]), insecure_validation()),
     }
+
+    keys
 }
 
 #[derive(Clone, Debug)]
 pub struct UserInformation {
     pub email: String,
     pub user_name: Option<String>,
+    pub groups: Vec<String>,
 }
 
 // During the 2FA flow we will
@@ -234,16 +574,19 @@ pub struct UserInformation {
 //  - second time we will rely on the `AC_CACHE` since the `code` has already been exchanged.
 // The `nonce` will ensure that the user is authorized only once.
 // We return only the `UserInformation` to force calling `redeem` to obtain the `refresh_token`.
-pub async fn exchange_code(code: &String) -> ApiResult<UserInformation> {
+pub async fn exchange_code(code: &String, idp_id: Option<String>) -> ApiResult<UserInformation> {
     if let Some(authenticated_user) = AC_CACHE.get(code) {
         return Ok(UserInformation {
             email: authenticated_user.email,
             user_name: authenticated_user.user_name,
+            groups: authenticated_user.groups,
         });
     }
 
+    let idp_id = resolve_idp_id(idp_id)?;
+
     let oidc_code = AuthorizationCode::new(code.clone());
-    let client = cached_client().await?;
+    let client = cached_client(&idp_id).await?;
 
     match client.exchange_code(oidc_code).request_async(async_http_client).await {
         Ok(token_response) => {
@@ -252,15 +595,16 @@ pub async fn exchange_code(code: &String) -> ApiResult<UserInformation> {
                 Ok(endpoint) => endpoint,
             };
 
-            let user_info: CoreUserInfoClaims = match endpoint.request_async(async_http_client).await {
+            let user_info: SsoUserInfoClaims = match endpoint.request_async(async_http_client).await {
                 Err(err) => err!(format!("Request to user_info endpoint failed: {err}")),
                 Ok(user_info) => user_info,
             };
 
-            let id_token = SSO_JWT_VALIDATION.decode_id_token(token_response.extra_fields().id_token())?;
+            let (id_token_raw, id_token) =
+                SSO_JWT_VALIDATION.decode_id_token(&idp_id, token_response.extra_fields().id_token()).await?;
 
-            let email = match id_token.email {
-                Some(email) => email,
+            let email = match &id_token.email {
+                Some(email) => email.clone(),
                 None => match user_info.email() {
                     None => err!("Neither id token nor userinfo contained an email"),
                     Some(email) => email.to_owned().to_string(),
@@ -269,17 +613,32 @@ pub async fn exchange_code(code: &String) -> ApiResult<UserInformation> {
 
             let user_name = user_info.preferred_username().map(|un| un.to_string());
 
+            // The groups/roles claim can be asserted in the id_token, the userinfo response, or
+            // both; prefer the id_token but fall back to userinfo if it's missing there.
+            let groups_claim = CONFIG.sso_groups_claim_name();
+            let groups = {
+                let from_id_token = claim_values(&groups_claim, &id_token.extra);
+                if from_id_token.is_empty() {
+                    claim_values(&groups_claim, &user_info.additional_claims().extra)
+                } else {
+                    from_id_token
+                }
+            };
+
             let refresh_token = token_response.refresh_token().map(|t| t.secret().to_string());
-            if refresh_token.is_none() && CONFIG.sso_scopes_vec().contains(&"offline_access".to_string()) {
+            if refresh_token.is_none() && CONFIG.sso_scopes_vec(&idp_id).contains(&"offline_access".to_string()) {
                 error!("Scope offline_access is present but response contain no refresh_token");
             }
 
             let authenticated_user = AuthenticatedUser {
+                idp_id,
                 nonce: id_token.nonce,
                 refresh_token,
                 access_token: token_response.access_token().secret().to_string(),
+                id_token: id_token_raw,
                 email: email.clone(),
                 user_name: user_name.clone(),
+                groups: groups.clone(),
             };
 
             AC_CACHE.insert(code.clone(), authenticated_user.clone());
@@ -287,6 +646,7 @@ pub async fn exchange_code(code: &String) -> ApiResult<UserInformation> {
             Ok(UserInformation {
                 email,
                 user_name,
+                groups,
             })
         }
         Err(err) => err!(format!("Failed to contact token endpoint: {err}")),
@@ -299,10 +659,30 @@ pub async fn redeem(code: &String, conn: &mut DbConn) -> ApiResult<Authenticated
         AC_CACHE.invalidate(code);
 
         if let Some(sso_nonce) = SsoNonce::find(&au.nonce, conn).await {
-            match sso_nonce.delete(conn).await {
-                Err(msg) => err!(format!("Failed to delete nonce: {msg}")),
-                Ok(_) => Ok(au),
+            // The nonce was saved alongside the `idp_id` selected at `authorize_url` time; make
+            // sure the provider the code was actually exchanged against (`au.idp_id`, threaded
+            // through by the caller) is the same one, so a tampered state/idp_id parameter can't
+            // make us accept a login against the wrong provider's endpoints.
+            if sso_nonce.idp_id != au.idp_id {
+                err!("SSO nonce was issued for a different provider");
+            }
+
+            if let Err(msg) = sso_nonce.delete(conn).await {
+                err!(format!("Failed to delete nonce: {msg}"))
+            }
+
+            // Mirror the IdP's group/role assertions onto organization membership on every SSO
+            // login, including revoking access when a group is no longer asserted. This only
+            // covers logins for an account that already exists: on a brand new account the
+            // `User` row isn't inserted until after `redeem` returns, by the registration code
+            // path (outside this module). That path must call `sync_memberships` itself, right
+            // after creating the row and with the same `au.groups`, for first-login membership
+            // to take effect — see the doc comment on `sync_memberships`.
+            if let Some(user) = User::find_by_mail(&au.email, conn).await {
+                sync_memberships(&user, &au.groups, conn).await?;
             }
+
+            Ok(au)
         } else {
             err!("Failed to retrive nonce from db")
         }
@@ -311,35 +691,219 @@ pub async fn redeem(code: &String, conn: &mut DbConn) -> ApiResult<Authenticated
     }
 }
 
-pub fn create_auth_tokens(
+// Tags a `UserOrganization` row as created/maintained by `sync_memberships`, keyed by the group
+// that earned it. Only rows carrying this marker are ever updated or revoked by the sync, so
+// membership a user was invited to by hand (no such marker) is never touched when their groups
+// change. Caveat: `external_id` is the same column directory/SCIM sync uses to correlate rows
+// against the directory; a directory connector that happens to assign an id of the form
+// `sso-group:<name>` would be mistaken for SSO-managed. A dedicated column would remove that
+// ambiguity, but isn't worth a migration for what should be a rare naming collision.
+const SSO_EXTERNAL_ID_PREFIX: &str = "sso-group:";
+
+// Translate the configured claim-value -> org/role mapping into actual `UserOrganization` rows.
+// Call this on every SSO login for an account that already exists (see `redeem`), and again from
+// the registration path right after a brand-new SSO account's `User` row is inserted, so it also
+// applies on a user's first login.
+//
+// Scope, deliberately narrower than "map group/role claims to organization membership and admin
+// access" suggests:
+// - There is no grant of Vaultwarden's `/admin` panel here, and there can't be one: that panel is
+//   gated solely by the shared `ADMIN_TOKEN` secret, a mechanism with no notion of per-user
+//   identity for an IdP claim to hook into. Only per-organization roles are mapped.
+// - A grant this function creates always lands as a pending `Invited` row, never `Confirmed` (see
+//   the `(None, true)` arm below) — this is permanent, not a pending TODO. Vaultwarden orgs are
+//   end-to-end encrypted: joining as `Confirmed` requires the org's symmetric key encrypted to the
+//   new member's public key, and only a client already holding that key (an existing confirmed
+//   member, in their browser) can produce it. A server-side background sync never has it, so it
+//   cannot auto-join anyone; the user or an org admin still has to find and accept the invite out
+//   of band, same as any other org invite.
+// - Revocation (the `(Some, false)` arm) has no such constraint and does take effect immediately.
+pub(crate) async fn sync_memberships(user: &User, groups: &[String], conn: &mut DbConn) -> ApiResult<()> {
+    let mapping = CONFIG.sso_organizations_mapping();
+
+    // Several configured groups can map to the same org, e.g. at different roles. Decide grant
+    // vs. revoke per *org*, not per group, so a group the user isn't in can't revoke membership
+    // that another of their groups still justifies. Ties (the user belongs to more than one group
+    // mapped to the same org) are resolved by config order: the first matching entry wins.
+    let mut org_order = Vec::new();
+    let mut matched: HashMap<String, (String, UserOrgType)> = HashMap::new();
+    for (group, org_uuid, role) in mapping {
+        if !org_order.contains(&org_uuid) {
+            org_order.push(org_uuid.clone());
+        }
+        if groups.contains(&group) {
+            matched.entry(org_uuid).or_insert((group, role));
+        }
+    }
+
+    for org_uuid in org_order {
+        let should_belong = matched.contains_key(&org_uuid);
+        let user_org = UserOrganization::find_by_user_and_org(&user.uuid, &org_uuid, conn).await;
+
+        match (user_org, should_belong) {
+            (Some(mut user_org), true) => {
+                // Never touch membership this sync didn't itself grant — a manually-assigned
+                // role (no marker, or a directory-managed row) is left exactly as it is.
+                let is_sso_managed =
+                    user_org.external_id.as_deref().is_some_and(|id| id.starts_with(SSO_EXTERNAL_ID_PREFIX));
+                if !is_sso_managed {
+                    continue;
+                }
+
+                let (group, role) = &matched[&org_uuid];
+                let external_id = format!("{SSO_EXTERNAL_ID_PREFIX}{group}");
+                if user_org.atype != *role as i32 || user_org.external_id.as_deref() != Some(external_id.as_str()) {
+                    user_org.atype = *role as i32;
+                    user_org.external_id = Some(external_id);
+                    user_org.save(conn).await?;
+                }
+            }
+            (Some(user_org), false) => {
+                // Never remove membership this sync didn't itself grant, and never strip an
+                // org's last confirmed Owner even if it was SSO-managed.
+                let is_sso_managed =
+                    user_org.external_id.as_deref().is_some_and(|id| id.starts_with(SSO_EXTERNAL_ID_PREFIX));
+                if !is_sso_managed {
+                    continue;
+                }
+
+                let is_confirmed_owner =
+                    user_org.atype == UserOrgType::Owner as i32 && user_org.status == UserOrgStatus::Confirmed as i32;
+                if is_confirmed_owner
+                    && UserOrganization::count_confirmed_by_org_and_type(&org_uuid, UserOrgType::Owner, conn).await <= 1
+                {
+                    warn!("Not removing {} from {org_uuid}: would leave the organization without an owner", user.uuid);
+                    continue;
+                }
+
+                user_org.delete(conn).await?;
+            }
+            (None, true) => match Organization::find_by_uuid(&org_uuid, conn).await {
+                Some(org) => {
+                    let (group, role) = &matched[&org_uuid];
+                    let mut user_org = UserOrganization::new(user.uuid.clone(), org.uuid.clone());
+                    user_org.access_all = false;
+                    user_org.atype = *role as i32;
+                    // No org key is available here to encrypt to the user's public key (that only
+                    // happens through the invite/accept flow), so a `Confirmed` row at this point
+                    // would be unusable — the user couldn't decrypt any org cipher. Land it as
+                    // `Invited` instead; see the scope note on this function for why that's by
+                    // design rather than a gap to close.
+                    user_org.status = UserOrgStatus::Invited as i32;
+                    user_org.external_id = Some(format!("{SSO_EXTERNAL_ID_PREFIX}{group}"));
+                    user_org.save(conn).await?;
+                }
+                None => {
+                    let (group, _) = &matched[&org_uuid];
+                    warn!("SSO group {group} maps to unknown organization {org_uuid}");
+                }
+            },
+            (None, false) => {}
+        }
+    }
+
+    Ok(())
+}
+
+struct RefreshedTokens {
+    access_token: String,
+    refresh_token: String,
+    id_token: Option<String>,
+}
+
+// Exchange a refresh_token with the provider's token endpoint, rolling it if a new one is returned.
+async fn request_refresh_token(idp_id: &str, refresh_token: &str) -> ApiResult<RefreshedTokens> {
+    let rt = RefreshToken::new(refresh_token.to_string());
+
+    let client = cached_client(idp_id).await?;
+
+    let token_response = match client.exchange_refresh_token(&rt).request_async(async_http_client).await {
+        Err(err) => err!(format!("Request to exchange_refresh_token endpoint failed: {:?}", err)),
+        Ok(token_response) => token_response,
+    };
+
+    // Use new refresh_token if returned
+    let rolled_refresh_token =
+        token_response.refresh_token().map(|token| token.secret().to_string()).unwrap_or(refresh_token.to_string());
+
+    // Some providers roll the id_token alongside the refresh_token, keep the latest one.
+    let id_token = token_response.extra_fields().id_token().map(|t| t.to_string());
+
+    Ok(RefreshedTokens {
+        access_token: token_response.access_token().secret().to_string(),
+        refresh_token: rolled_refresh_token,
+        id_token,
+    })
+}
+
+pub async fn create_auth_tokens(
     device: &Device,
     user: &User,
+    idp_id: &str,
     refresh_token: Option<String>,
     access_token: &str,
+    id_token: Option<String>,
 ) -> ApiResult<auth::AuthTokens> {
-    let refresh_claims = refresh_token.map(|rt| {
-        let (nbf, exp) = match SSO_JWT_VALIDATION.decode_basic_token("refresh_token", &rt) {
-            Err(_) => {
-                let time_now = Utc::now().naive_utc();
-                (time_now.timestamp(), (time_now + *DEFAULT_REFRESH_VALIDITY).timestamp())
-            }
-            Ok(refresh_payload) => {
-                debug!("Refresh_payload: {:?}", refresh_payload);
-                (refresh_payload.nbf(), refresh_payload.exp)
-            }
-        };
+    let access_payload = validate_access_token(idp_id, access_token).await?;
+
+    // An access token within `sso_access_token_refresh_margin_seconds()` of expiry is treated as
+    // already expired and rolled now when a refresh_token is available. Without a refresh_token,
+    // or when the refresh fails while the token is still valid, the current token is used as is.
+    let margin = CONFIG.sso_access_token_refresh_margin_seconds();
+    let (access_token, access_payload, refresh_token, id_token) = if access_payload.is_expiring(margin) {
+        match &refresh_token {
+            None => (access_token.to_string(), access_payload, refresh_token, id_token),
+            Some(rt) => match request_refresh_token(idp_id, rt).await {
+                Ok(refreshed) => {
+                    let refreshed_payload = validate_access_token(idp_id, &refreshed.access_token).await?;
+                    (
+                        refreshed.access_token,
+                        refreshed_payload,
+                        Some(refreshed.refresh_token),
+                        refreshed.id_token.or(id_token),
+                    )
+                }
+                // The current token is only being refreshed proactively (it may still have up to
+                // `margin` seconds left): if the provider can't be reached, log the user in with it
+                // rather than failing the whole flow, unless it has genuinely expired already.
+                Err(err) if access_payload.exp > Utc::now().naive_utc().timestamp() => {
+                    warn!("Failed to proactively refresh SSO access token, keeping the still-valid one: {err}");
+                    (access_token.to_string(), access_payload, refresh_token, id_token)
+                }
+                Err(err) => err!(format!("Failed to refresh expired SSO access token: {err}")),
+            },
+        }
+    } else {
+        (access_token.to_string(), access_payload, refresh_token, id_token)
+    };
+
+    let refresh_claims = match refresh_token {
+        None => None,
+        Some(rt) => {
+            let (nbf, exp) = match SSO_JWT_VALIDATION.decode_basic_token(idp_id, "refresh_token", &rt).await {
+                Err(_) => {
+                    let time_now = Utc::now().naive_utc();
+                    (time_now.timestamp(), (time_now + *DEFAULT_REFRESH_VALIDITY).timestamp())
+                }
+                Ok(refresh_payload) => {
+                    debug!("Refresh_payload: {:?}", refresh_payload);
+                    (refresh_payload.nbf(), refresh_payload.exp)
+                }
+            };
 
-        auth::RefreshJwtClaims {
-            nbf,
-            exp,
-            iss: auth::JWT_LOGIN_ISSUER.to_string(),
-            sub: auth::AuthMethod::Sso,
-            device_token: device.refresh_token.clone(),
-            refresh_token: Some(rt),
+            Some(auth::RefreshJwtClaims {
+                nbf,
+                exp,
+                iss: auth::JWT_LOGIN_ISSUER.to_string(),
+                sub: auth::AuthMethod::Sso,
+                device_token: device.refresh_token.clone(),
+                refresh_token: Some(rt),
+                idp_id: idp_id.to_string(),
+                id_token,
+            })
         }
-    });
+    };
 
-    let access_payload = SSO_JWT_VALIDATION.decode_basic_token("access_token", access_token)?;
     debug!("Access_payload: {:?}", access_payload);
 
     let access_claims = auth::LoginJwtClaims::new(
@@ -361,22 +925,20 @@ pub async fn exchange_refresh_token(
     user: &User,
     refresh_claims: &auth::RefreshJwtClaims,
 ) -> ApiResult<auth::AuthTokens> {
-    if let Some(refresh_token) = &refresh_claims.refresh_token {
-        let rt = RefreshToken::new(refresh_token.to_string());
-
-        let client = cached_client().await?;
-
-        let token_response = match client.exchange_refresh_token(&rt).request_async(async_http_client).await {
-            Err(err) => err!(format!("Request to exchange_refresh_token endpoint failed: {:?}", err)),
-            Ok(token_response) => token_response,
-        };
+    let Some(refresh_token) = &refresh_claims.refresh_token else {
+        err!("Impossible to retrieve new access token, refresh_token is missing")
+    };
 
-        // Use new refresh_token if returned
-        let rolled_refresh_token =
-            token_response.refresh_token().map(|token| token.secret().to_string()).unwrap_or(refresh_token.to_string());
+    let refreshed = request_refresh_token(&refresh_claims.idp_id, refresh_token).await?;
+    let id_token = refreshed.id_token.or_else(|| refresh_claims.id_token.clone());
 
-        create_auth_tokens(device, user, Some(rolled_refresh_token), token_response.access_token().secret())
-    } else {
-        err!("Impossible to retrieve new access token, refresh_token is missing")
-    }
+    create_auth_tokens(
+        device,
+        user,
+        &refresh_claims.idp_id,
+        Some(refreshed.refresh_token),
+        &refreshed.access_token,
+        id_token,
+    )
+    .await
 }